@@ -1,10 +1,15 @@
 use std::marker::PhantomData;
 
-use futures::{future::BoxFuture, Future};
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    stream, Future, Stream,
+};
 
 /// ShortBoxFuture<'b, 'a, T> is a future with a shorter lifetime than both 'a and 'b.
 /// It is equivalent to BoxFuture<'a + 'b, T> or
-/// BoxFuture<'b, T> where 'a > 'b.
+/// BoxFuture<'b, T> where 'a > 'b. Nothing about the struct is specific to
+/// `&'b A` vs. `&'b mut A`: the same type also works for a closure that
+/// re-borrows its argument mutably on every call (see `tests::with_retries_mut`).
 pub struct ShortBoxFuture<'b, 'a: 'b, T>(pub BoxFuture<'b, T>, PhantomData<&'a ()>);
 impl<'b, 'a: 'b, T, F: Future<Output = T> + Send + 'b> From<F> for ShortBoxFuture<'b, 'a, T> {
     fn from(f: F) -> Self {
@@ -12,9 +17,76 @@ impl<'b, 'a: 'b, T, F: Future<Output = T> + Send + 'b> From<F> for ShortBoxFutur
     }
 }
 
+/// ShortLocalBoxFuture<'b, 'a, T> is the non-`Send` counterpart of
+/// [`ShortBoxFuture`]: a future with a shorter lifetime than both 'a and 'b,
+/// for closures capturing `!Send` state such as `Rc<RefCell<_>>`. The `'b`
+/// ordering is the same as `ShortBoxFuture` (the HRTB lifetime comes first
+/// and must be the shorter one). Intended for single-threaded executors,
+/// e.g. `tokio::task::LocalSet`.
+pub struct ShortLocalBoxFuture<'b, 'a: 'b, T>(pub LocalBoxFuture<'b, T>, PhantomData<&'a ()>);
+impl<'b, 'a: 'b, T, F: Future<Output = T> + 'b> From<F> for ShortLocalBoxFuture<'b, 'a, T> {
+    fn from(f: F) -> Self {
+        Self(Box::pin(f), PhantomData)
+    }
+}
+
+/// Hidden helper behind [`short_future!`]. Re-borrowing through this
+/// generic newtype (and immediately projecting `.0`) is the same trick as
+/// the hand-written `WrapStr` in `test_retries_semi_inline`: the field
+/// access gets a fresh inference variable for its lifetime instead of being
+/// tied directly to the named HRTB lifetime, which is what lets the
+/// compiler accept a fully-inlined async block.
+#[doc(hidden)]
+pub struct ShortFutureWrap<'s, T: ?Sized>(pub &'s T);
+
+/// `short_future!(arg1, arg2, ... => { body })` is sugar for the manual
+/// `WrapStr(arg).0` trick from `test_retries_semi_inline`, applied to every
+/// named argument. Inside the `async` block it builds, it re-binds each
+/// argument through [`ShortFutureWrap`] before `body` runs, and the whole
+/// block is converted via `.into()`. The re-binding has to happen inside
+/// the `async` block (not before it) so that only the Copy reference
+/// arguments, not anything else the body borrows from the enclosing scope,
+/// need to be captured specially. This is what lets a fully-inlined body
+/// that directly borrows its arguments compile instead of hitting "async
+/// block may outlive the current function". Works for any of the
+/// `ShortBoxFuture*` types, single- or multi-argument, since the target
+/// type is inferred from context.
+#[macro_export]
+macro_rules! short_future {
+    ($($arg:ident),+ $(,)? => $body:block) => {{
+        (async {
+            $(
+                let $arg = $crate::ShortFutureWrap($arg).0;
+            )+
+            $body
+        })
+        .into()
+    }};
+}
+
+/// Builds a stream that, for every item, re-borrows the state produced by
+/// `state_factory` mutably through `f` and awaits the single item `f`
+/// produces, releasing the borrow before the next item is requested. This
+/// is the same re-borrow trick as `tests::with_retries_mut`, applied per
+/// stream item instead of per retry attempt: `f` returns `None` once the
+/// state is exhausted, ending the stream. Each call to `f` only ever needs
+/// to produce one item, so it returns a plain `ShortBoxFuture` rather than a
+/// stream of its own.
+pub fn poll_each<'a, S: 'a, T: 'a, SF: FnOnce() -> S>(
+    state_factory: SF,
+    f: impl for<'b> FnMut(&'b mut S) -> ShortBoxFuture<'b, 'a, Option<T>> + 'a,
+) -> impl Stream<Item = T> + 'a {
+    stream::unfold((state_factory(), f), move |(mut state, mut f)| async move {
+        let item = f(&mut state).0.await;
+        item.map(|item| (item, (state, f)))
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use super::ShortBoxFuture;
+    use super::{poll_each, ShortBoxFuture, ShortLocalBoxFuture};
+    use futures::StreamExt;
+    use std::{cell::RefCell, rc::Rc};
 
     pub async fn with_retries<'a, F>(f: F) -> usize
     where
@@ -77,6 +149,210 @@ mod tests {
         .await;
         assert_eq!(result, 11);
     }
+
+    pub async fn with_retries_mut<'a, F>(mut f: F) -> usize
+    where
+        F: for<'b> FnMut(&'b mut i32) -> ShortBoxFuture<'b, 'a, Result<(), ()>>,
+    {
+        for i in 0..100 {
+            // Imagine this is a `&mut Connection` that must be re-borrowed
+            // every iteration because it can't be cloned or moved.
+            let mut attempt = i;
+            let result = f(&mut attempt).0.await;
+            match result {
+                Ok(()) => return i as usize,
+                Err(()) => {}
+            }
+        }
+        0
+    }
+
+    /// Each iteration re-borrows `&mut i32` and the borrow ends the moment
+    /// the returned future completes, so this compiles without triggering
+    /// "borrowed more than once" even though `func` is called in a loop.
+    #[tokio::test]
+    async fn test_retries_mut_closure() {
+        let result = with_retries_mut(|attempt| {
+            async move {
+                *attempt += 1;
+                if *attempt == 11 {
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            }
+            .into()
+        })
+        .await;
+        assert_eq!(result, 10);
+    }
+
+    pub async fn call_changer<'b>(i: &'b mut i32) -> Result<(), ()> {
+        *i *= 2;
+        if *i == 20 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_mut_fn() {
+        let result = with_retries_mut(|attempt| call_changer(attempt).into()).await;
+        assert_eq!(result, 10);
+    }
+
+    /// Compare to `failing_tests::test_retries_fully_inline`, which inlines
+    /// `session == &data` directly and fails to compile. `short_future!`
+    /// applies the `WrapStr` trick from `test_retries_semi_inline`
+    /// automatically, so the inlined comparison compiles without a
+    /// hand-written wrapper.
+    #[tokio::test]
+    async fn test_retries_macro_fully_inline() {
+        let data = format!("11 transaction");
+        let result = with_retries(|session| {
+            short_future!(session => {
+                if session == &data {
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            })
+        })
+        .await;
+        assert_eq!(result, 11);
+    }
+
+    /// Like `with_retries`, but `f` takes two arguments sharing the same
+    /// HRTB lifetime `'b`: the per-attempt `transaction` and `limit`, an
+    /// enclosing-scope value re-borrowed on every call. Both borrows are
+    /// released the moment each future completes, same as `with_retries`.
+    /// This is the `for<'b> Fn(&'b A, &'b B) -> ShortBoxFuture<'b, 'a, T>`
+    /// shape: sharing one lifetime for both arguments is required, not just
+    /// convenient — a closure can't be driven with two *independent* HRTB
+    /// lifetimes at all (see
+    /// `failing_tests::with_retries2_independent_lifetimes`), so there is no
+    /// two-argument equivalent of `ShortBoxFuture` for that case.
+    pub async fn with_retries2<'a, F>(f: F) -> usize
+    where
+        F: for<'b> Fn(&'b str, &'b i32) -> ShortBoxFuture<'b, 'a, Result<(), ()>>,
+    {
+        let limit: i32 = 100;
+        for i in 0..100 {
+            let transaction = format!("{i} transaction");
+            let result = f(&transaction, &limit).0.await;
+            drop(transaction);
+            match result {
+                Ok(()) => return i,
+                Err(()) => {}
+            }
+        }
+        0
+    }
+
+    pub async fn two_arg_check<'b>(session: &'b str, limit: &'b i32, data: &str) -> Result<(), ()> {
+        if session == data && *limit == 100 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries2_fn() {
+        let data = format!("11 transaction");
+        let result =
+            with_retries2(|session, limit| two_arg_check(session, limit, &data).into()).await;
+        assert_eq!(result, 11);
+    }
+
+    /// Same as `test_retries2_fn`, but the body is inlined directly instead
+    /// of delegating to a named function, using `short_future!` to wrap both
+    /// borrowed arguments at once.
+    #[tokio::test]
+    async fn test_retries2_macro_inline() {
+        let data = format!("11 transaction");
+        let result = with_retries2(|session, limit| {
+            short_future!(session, limit => {
+                if session == &data && *limit == 100 {
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            })
+        })
+        .await;
+        assert_eq!(result, 11);
+    }
+
+    pub async fn with_retries_local<'a, F>(f: F) -> usize
+    where
+        F: for<'b> Fn(&'b str) -> ShortLocalBoxFuture<'b, 'a, Result<(), ()>>,
+    {
+        for i in 0..100 {
+            // Imagine this is an `Rc<RefCell<Processor>>` that can't be sent
+            // across threads, so it's re-borrowed every iteration instead.
+            let transaction = format!("{i} transaction");
+            let result = f(&transaction).0.await;
+            drop(transaction);
+            match result {
+                Ok(()) => return i,
+                Err(()) => {}
+            }
+        }
+        0
+    }
+
+    /// Demonstrates the re-borrow trick with a `!Send` closure capturing
+    /// `Rc<RefCell<_>>`, which `ShortBoxFuture` cannot accept because of its
+    /// `Send` bound. Runs inside a `LocalSet`, as real callers would on
+    /// `tokio::task::LocalSet`/`spawn_local`.
+    #[tokio::test]
+    async fn test_retries_local_rc_refcell() {
+        let local = tokio::task::LocalSet::new();
+        let result = local
+            .run_until(async {
+                let data = Rc::new(RefCell::new(format!("11 transaction")));
+                with_retries_local(|session| {
+                    let data = Rc::clone(&data);
+                    async move {
+                        let expected = data.borrow().clone();
+                        str_eq(session, &expected).await
+                    }
+                    .into()
+                })
+                .await
+            })
+            .await;
+        assert_eq!(result, 11);
+    }
+
+    /// Drives the stream from `poll_each` to completion, proving the
+    /// per-item borrow of `device` is released between items (the device
+    /// itself is never moved or cloned, only re-borrowed).
+    #[tokio::test]
+    async fn test_poll_each_drops_state_between_items() {
+        struct Device {
+            frames: Vec<String>,
+        }
+
+        let mut stream = Box::pin(poll_each(
+            || Device {
+                frames: vec!["frame0".to_string(), "frame1".to_string()],
+            },
+            |device| {
+                let frame = if device.frames.is_empty() {
+                    None
+                } else {
+                    Some(device.frames.remove(0))
+                };
+                async move { frame }.into()
+            },
+        ));
+        assert_eq!(stream.next().await, Some("frame0".to_string()));
+        assert_eq!(stream.next().await, Some("frame1".to_string()));
+        assert_eq!(stream.next().await, None);
+    }
 }
 
 /// You may be thinking "the borrow checker is smart and I'm clever.
@@ -195,6 +471,9 @@ mod failing_tests {
     ///
     /// Error: "async block may outlive the current function, but it borrows
     /// `session`, which is owned by the current function"
+    ///
+    /// See `super::tests::test_retries_macro_fully_inline` for the fix:
+    /// `short_future!` applies the WrapStr trick for you.
     #[cfg(any())]
     #[tokio::test]
     async fn test_retries_fully_inline() {
@@ -227,4 +506,38 @@ mod failing_tests {
             drop(transaction);
         }
     }
+
+    /// A type shaped exactly like `ShortBoxFuture` but carrying a second,
+    /// independent short lifetime ('b and 'c, both shorter than 'a) doesn't
+    /// help either: a plain closure can't actually be driven with *two
+    /// independent* HRTB lifetimes the way `with_retries2` is driven with
+    /// one shared `'b` (see `super::tests::with_retries2`). The compiler
+    /// can't generalize a closure's inferred signature over both `'b` and
+    /// `'c` independently, even when the body is just a function call.
+    ///
+    /// Error: "closure was supposed to return data with lifetime '2 but it
+    /// is returning data with lifetime '1" (and the flipped version of the
+    /// same error)
+    #[cfg(any())]
+    struct ShortBoxFuture2<'b, 'c, 'a: 'b + 'c, T>(BoxFuture<'b, T>, PhantomData<(&'c (), &'a ())>);
+    #[cfg(any())]
+    impl<'b, 'c, 'a: 'b + 'c, T, F: Future<Output = T> + Send + 'b + 'c> From<F>
+        for ShortBoxFuture2<'b, 'c, 'a, T>
+    {
+        fn from(f: F) -> Self {
+            Self(Box::pin(f), PhantomData)
+        }
+    }
+    #[cfg(any())]
+    async fn with_retries2_independent_lifetimes<'a, F>(f: F)
+    where
+        F: for<'b, 'c> Fn(&'b str, &'c i32) -> ShortBoxFuture2<'b, 'c, 'a, ()>,
+    {
+        let limit = 100;
+        for i in 0..100 {
+            let transaction = format!("{i} transaction");
+            f(&transaction, &limit).0.await;
+            drop(transaction);
+        }
+    }
 }